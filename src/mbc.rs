@@ -1,10 +1,15 @@
 use extra::time;
 
+// 5 latched + 5 current RTC registers (as little-endian u32 words) plus an
+// 8-byte little-endian unix timestamp of the last save, BGB/VBA-M style.
+static RTC_FOOTER_LEN: uint = 5*4 + 5*4 + 8;
+
 pub trait MBC {
 	fn readrom(&self, a: u16) -> u8;
 	fn readram(&self, a: u16) -> u8;
 	fn writerom(&mut self, a: u16, v: u8);
 	fn writeram(&mut self, a: u16, v: u8);
+	fn save(&self);
 }
 
 struct MBC0 {
@@ -25,6 +30,7 @@ struct MBC1 {
 	priv rombank: u32,
 	priv rambank: u32,
 	priv savepath: Option<Path>,
+	priv dirty: ::std::cell::Cell<bool>,
 }
 
 impl MBC1 {
@@ -43,6 +49,7 @@ impl MBC1 {
 			rombank: 1,
 			rambank: 0,
 			savepath: svpath,
+			dirty: ::std::cell::Cell::new(false),
 		};
 		res.loadram();
 		return res
@@ -60,13 +67,54 @@ impl MBC1 {
 
 impl Drop for MBC1 {
 	fn drop(&mut self) {
+		self.save();
+	}
+}
+
+struct MBC2 {
+	priv rom: ~[u8],
+	priv ram: ~[u8],
+	priv ram_on: bool,
+	priv rombank: u32,
+	priv savepath: Option<Path>,
+	priv dirty: ::std::cell::Cell<bool>,
+}
+
+impl MBC2 {
+	pub fn new(data: ~[u8], file: &Path) -> MBC2 {
+		let svpath = match data[0x147] {
+			0x06 => Some(file.with_extension("gbsave")),
+			_ => None,
+		};
+
+		let mut res = MBC2 {
+			rom: data,
+			ram: ::std::vec::from_elem(0x200, 0u8),
+			ram_on: false,
+			rombank: 1,
+			savepath: svpath,
+			dirty: ::std::cell::Cell::new(false),
+		};
+		res.loadram();
+		return res
+	}
+
+	fn loadram(&mut self) {
 		match self.savepath.clone() {
 			None => {},
-			Some(path) => ::std::io::File::create(&path).write(self.ram),
+			Some(savepath) => if savepath.is_file() {
+					self.ram = ::std::io::File::open(&savepath).read_to_end();
+			},
 		};
 	}
 }
 
+impl Drop for MBC2 {
+	fn drop(&mut self) {
+		self.save();
+	}
+}
+
 struct MBC3 {
 	priv rom: ~[u8],
 	priv ram: ~[u8],
@@ -75,8 +123,9 @@ struct MBC3 {
 	priv ram_on: bool,
 	priv savepath: Option<Path>,
 	priv rtc_ram: ~[u8,.. 5],
-	priv rtc_lock: bool,
 	priv rtc_zero: Option<i64>,
+	priv prev_latch: u8,
+	priv dirty: ::std::cell::Cell<bool>,
 }
 
 impl MBC3 {
@@ -103,8 +152,9 @@ impl MBC3 {
 			ram_on: false,
 			savepath: svpath,
 			rtc_ram: ~([0u8,.. 5]),
-			rtc_lock: false,
 			rtc_zero: rtc,
+			prev_latch: 0xFF,
+			dirty: ::std::cell::Cell::new(false),
 		};
 		res.loadram();
 		return res
@@ -115,13 +165,67 @@ impl MBC3 {
 			None => {},
 			Some(savepath) => if savepath.is_file() {
 				let mut file = ::std::io::File::open(&savepath);
-				let rtc = file.read_be_i64();
-				if self.rtc_zero.is_some() { self.rtc_zero = Some(rtc); }
-				self.ram = file.read_to_end();
+				let data = file.read_to_end();
+				let ramlen = self.ram.len();
+				if self.rtc_zero.is_some() && data.len() == ramlen + RTC_FOOTER_LEN {
+					// BGB/VBA-M style save: RAM followed by an RTC footer.
+					self.ram = data.slice(0, ramlen).to_owned();
+					let mut footer = ::std::io::mem::MemReader::new(data.slice(ramlen, data.len()).to_owned());
+					self.rtc_ram[0] = footer.read_le_u32() as u8;
+					self.rtc_ram[1] = footer.read_le_u32() as u8;
+					self.rtc_ram[2] = footer.read_le_u32() as u8;
+					self.rtc_ram[3] = footer.read_le_u32() as u8;
+					self.rtc_ram[4] = footer.read_le_u32() as u8;
+					let cur_sec = footer.read_le_u32() as i64;
+					let cur_min = footer.read_le_u32() as i64;
+					let cur_hour = footer.read_le_u32() as i64;
+					let cur_day_lo = footer.read_le_u32() as i64;
+					let cur_day_hi = footer.read_le_u32() as i64;
+					let saved_at = footer.read_le_u64() as i64;
+					let days = ((cur_day_hi & 0x01) << 8) | cur_day_lo;
+					let elapsed = cur_sec + cur_min * 60 + cur_hour * 3600 + days * 3600 * 24;
+					let halted = cur_day_hi & 0x40 == 0x40;
+					self.rtc_zero = Some(if halted {
+						// Halted: freeze the clock rather than crediting offline time.
+						time::get_time().sec - elapsed
+					} else {
+						saved_at - elapsed
+					});
+				} else if self.rtc_zero.is_some() && data.len() == ramlen + 8 {
+					// Old rBoy-only format: a big-endian rtc_zero followed by raw RAM.
+					let mut legacy = ::std::io::mem::MemReader::new(data);
+					let rtc = legacy.read_be_i64();
+					self.rtc_zero = Some(rtc);
+					self.ram = legacy.read_to_end();
+				} else {
+					// Plain RAM+battery cart (no RTC): the save is just raw RAM.
+					self.ram = data;
+				}
 			},
 		};
 	}
 
+	fn current_regs(&self) -> (u8, u8, u8, u8, u8) {
+		let tzero = match self.rtc_zero {
+			Some(t) => t,
+			None => return (self.rtc_ram[0], self.rtc_ram[1], self.rtc_ram[2], self.rtc_ram[3], self.rtc_ram[4]),
+		};
+		if self.rtc_ram[4] & 0x40 == 0x40 {
+			return (self.rtc_ram[0], self.rtc_ram[1], self.rtc_ram[2], self.rtc_ram[3], self.rtc_ram[4]);
+		}
+		let difftime: i64 = match time::get_time().sec - tzero {
+			n if n >= 0 => { n },
+			_ => { 0 },
+		};
+		let sec = (difftime % 60) as u8;
+		let min = ((difftime / 60) % 60) as u8;
+		let hour = ((difftime / 3600) % 24) as u8;
+		let days: i64 = difftime / (3600*24);
+		let day_lo = days as u8;
+		let day_hi = (self.rtc_ram[4] & 0xFE) | (((days >> 8) & 0x01) as u8) | if days >= 512 { 0x80 } else { 0 };
+		(sec, min, hour, day_lo, day_hi)
+	}
+
 	fn calc_rtc_reg(&mut self) {
 		let tzero = match self.rtc_zero {
 			Some(t) => t,
@@ -159,28 +263,187 @@ impl MBC3 {
 
 impl Drop for MBC3 {
 	fn drop(&mut self) {
+		self.save();
+	}
+}
+
+struct MBC5 {
+	priv rom: ~[u8],
+	priv ram: ~[u8],
+	priv ram_on: bool,
+	priv rombank: u32,
+	priv rambank: u32,
+	priv rumble: bool,
+	priv savepath: Option<Path>,
+	priv dirty: ::std::cell::Cell<bool>,
+}
+
+impl MBC5 {
+	pub fn new(data: ~[u8], file: &Path) -> MBC5 {
+		let subtype = data[0x147];
+		let svpath = match subtype {
+			0x1B | 0x1E => Some(file.with_extension("gbsave")),
+			_ => None,
+		};
+		let ramsize = match subtype {
+			0x1A | 0x1B | 0x1D | 0x1E => ram_size(data[0x149]),
+			_ => 0,
+		};
+		let rumble = match subtype {
+			0x1C | 0x1D | 0x1E => true,
+			_ => false,
+		};
+
+		let mut res = MBC5 {
+			rom: data,
+			ram: ::std::vec::from_elem(ramsize, 0u8),
+			ram_on: false,
+			rombank: 1,
+			rambank: 0,
+			rumble: rumble,
+			savepath: svpath,
+			dirty: ::std::cell::Cell::new(false),
+		};
+		res.loadram();
+		return res
+	}
+
+	fn loadram(&mut self) {
+		match self.savepath.clone() {
+			None => {},
+			Some(savepath) => if savepath.is_file() {
+					self.ram = ::std::io::File::open(&savepath).read_to_end();
+			},
+		};
+	}
+
+	fn rambank_idx(&self) -> u32 {
+		// Bit 3 of the RAM-bank register is the rumble motor on rumble carts,
+		// not a bank select bit.
+		if self.rumble { self.rambank & 0x07 } else { self.rambank & 0x0F }
+	}
+}
+
+impl Drop for MBC5 {
+	fn drop(&mut self) {
+		self.save();
+	}
+}
+
+struct HuC1 {
+	priv rom: ~[u8],
+	priv ram: ~[u8],
+	priv ram_on: bool,
+	priv ir_mode: bool,
+	priv ram_mode: bool,
+	priv rombank: u32,
+	priv rambank: u32,
+	priv savepath: Option<Path>,
+	priv dirty: ::std::cell::Cell<bool>,
+}
+
+impl HuC1 {
+	pub fn new(data: ~[u8], file: &Path) -> HuC1 {
+		let ramsize = ram_size(data[0x149]);
+		let mut res = HuC1 {
+			rom: data,
+			ram: ::std::vec::from_elem(ramsize, 0u8),
+			ram_on: false,
+			ir_mode: false,
+			ram_mode: false,
+			rombank: 1,
+			rambank: 0,
+			savepath: Some(file.with_extension("gbsave")),
+			dirty: ::std::cell::Cell::new(false),
+		};
+		res.loadram();
+		return res
+	}
+
+	fn loadram(&mut self) {
 		match self.savepath.clone() {
 			None => {},
-			Some(path) => {
-				let mut file = ::std::io::File::create(&path);
-				let rtc = match (self.rtc_zero) {
-					Some(t) => t,
-					None => 0,
-				};
-				file.write_be_i64(rtc);
-				file.write(self.ram);
+			Some(savepath) => if savepath.is_file() {
+					self.ram = ::std::io::File::open(&savepath).read_to_end();
 			},
 		};
 	}
 }
 
+impl Drop for HuC1 {
+	fn drop(&mut self) {
+		self.save();
+	}
+}
+
+struct HuC3 {
+	priv rom: ~[u8],
+	priv ram: ~[u8],
+	priv ram_on: bool,
+	priv rombank: u32,
+	priv rambank: u32,
+	priv savepath: Option<Path>,
+	priv dirty: ::std::cell::Cell<bool>,
+	priv hc_command: u8,
+}
+
+impl HuC3 {
+	pub fn new(data: ~[u8], file: &Path) -> HuC3 {
+		let ramsize = ram_size(data[0x149]);
+		let mut res = HuC3 {
+			rom: data,
+			ram: ::std::vec::from_elem(ramsize, 0u8),
+			ram_on: false,
+			rombank: 1,
+			rambank: 0,
+			savepath: Some(file.with_extension("gbsave")),
+			dirty: ::std::cell::Cell::new(false),
+			hc_command: 0,
+		};
+		res.loadram();
+		return res
+	}
+
+	fn loadram(&mut self) {
+		match self.savepath.clone() {
+			None => {},
+			Some(savepath) => if savepath.is_file() {
+					self.ram = ::std::io::File::open(&savepath).read_to_end();
+			},
+		};
+	}
+
+	// Minimal HuC3 clock/command register: just enough for games to read
+	// back a plausible running clock instead of locking up.
+	fn hc_register(&self) -> u8 {
+		let t = time::get_time().sec;
+		match self.hc_command & 0x0F {
+			0x0 => ((t / 60) % 60) as u8,
+			0x1 => ((t / 3600) % 24) as u8,
+			0x2 => ((t / 86400) & 0xFF) as u8,
+			0x3 => (((t / 86400) >> 8) & 0x0F) as u8,
+			_ => 0,
+		}
+	}
+}
+
+impl Drop for HuC3 {
+	fn drop(&mut self) {
+		self.save();
+	}
+}
+
 pub fn get_mbc(file: &Path) -> ~MBC {
 	let data: ~[u8] = ::std::io::File::open(file).read_to_end();
 	if data.len() < 0x149 { fail!("Rom size to small"); }
 	match data[0x147] {
 		0x00 => ~MBC0::new(data) as ~MBC,
 		0x01 .. 0x03 => ~MBC1::new(data, file) as ~MBC,
+		0x05 .. 0x06 => ~MBC2::new(data, file) as ~MBC,
 		0x0F .. 0x13 => ~MBC3::new(data, file) as ~MBC,
+		0x19 .. 0x1E => ~MBC5::new(data, file) as ~MBC,
+		0xFE => ~HuC3::new(data, file) as ~MBC,
+		0xFF => ~HuC1::new(data, file) as ~MBC,
 		m => fail!("Unsupported MBC type: {:02X}", m),
 	}
 }
@@ -190,6 +453,7 @@ fn ram_size(v: u8) -> uint {
 		1 => 0x800,
 		2 => 0x2000,
 		3 => 0x8000,
+		4 => 0x20000,
 		_ => 0,
 	}
 }
@@ -199,6 +463,7 @@ impl MBC for MBC0 {
 	fn readram(&self, _a: u16) -> u8 { 0 }
 	fn writerom(&mut self, _a: u16, _v: u8) { () }
 	fn writeram(&mut self, _a: u16, _v: u8) { () }
+	fn save(&self) { () }
 }
 
 impl MBC for MBC1 {
@@ -234,6 +499,54 @@ impl MBC for MBC1 {
 		if !self.ram_on { return }
 		let rambank = if self.ram_mode { self.rambank } else { 0 };
 		self.ram[rambank * 0x2000 | a as u32] = v;
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => ::std::io::File::create(&path).write(self.ram),
+		};
+		self.dirty.set(false);
+	}
+}
+
+impl MBC for MBC2 {
+	fn readrom(&self, a: u16) -> u8 {
+		if a < 0x4000 { self.rom[a] }
+		else { self.rom[self.rombank * 0x4000 | ((a as u32) & 0x3FFF)] }
+	}
+	fn readram(&self, a: u16) -> u8 {
+		if !self.ram_on { return 0 }
+		self.ram[(a as u32) & 0x1FF] | 0xF0
+	}
+	fn writerom(&mut self, a: u16, v: u8) {
+		match a {
+			0x0000 .. 0x3FFF => {
+				if a & 0x100 == 0 {
+					self.ram_on = (v & 0x0F) == 0x0A;
+				} else {
+					self.rombank = match (v as u32) & 0x0F { 0 => 1, n => n };
+				}
+			},
+			0x4000 .. 0x7FFF => {},
+			_ => fail!("Could not write to {:04X} (MBC2)", a),
+		}
+	}
+	fn writeram(&mut self, a: u16, v: u8) {
+		if !self.ram_on { return }
+		self.ram[(a as u32) & 0x1FF] = v & 0x0F;
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => ::std::io::File::create(&path).write(self.ram),
+		};
+		self.dirty.set(false);
 	}
 }
 
@@ -257,13 +570,9 @@ impl MBC for MBC3 {
 				self.rombank = match v & 0x7F { 0 => 1, n => n as u32 }
 			},
 			0x4000 .. 0x5FFF => self.rambank = v as u32,
-			0x6000 .. 0x7FFF => match v {
-				0 => self.rtc_lock = false,
-				1 => {
-					if !self.rtc_lock { self.calc_rtc_reg(); };
-					self.rtc_lock = true;
-				},
-				_ => {},
+			0x6000 .. 0x7FFF => {
+				if self.prev_latch == 0x00 && v == 0x01 { self.calc_rtc_reg(); }
+				self.prev_latch = v;
 			},
 			_ => fail!("Could not write to {:04X} (MBC3)", a),
 		}
@@ -276,5 +585,164 @@ impl MBC for MBC3 {
 			self.rtc_ram[self.rambank - 0x8] = v;
 			self.calc_rtc_zero();
 		}
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => {
+				let mut file = ::std::io::File::create(&path);
+				file.write(self.ram);
+				// Only RTC-equipped carts (subtypes 0x0F/0x10) get the BGB/VBA-M
+				// footer; a plain RAM+battery cart (0x13) must stay raw RAM so it
+				// stays portable with emulators that don't know about our RTC.
+				if self.rtc_zero.is_some() {
+					file.write_le_u32(self.rtc_ram[0] as u32);
+					file.write_le_u32(self.rtc_ram[1] as u32);
+					file.write_le_u32(self.rtc_ram[2] as u32);
+					file.write_le_u32(self.rtc_ram[3] as u32);
+					file.write_le_u32(self.rtc_ram[4] as u32);
+					let (sec, min, hour, day_lo, day_hi) = self.current_regs();
+					file.write_le_u32(sec as u32);
+					file.write_le_u32(min as u32);
+					file.write_le_u32(hour as u32);
+					file.write_le_u32(day_lo as u32);
+					file.write_le_u32(day_hi as u32);
+					file.write_le_u64(time::get_time().sec as u64);
+				}
+			},
+		};
+		self.dirty.set(false);
+	}
+}
+
+impl MBC for MBC5 {
+	fn readrom(&self, a: u16) -> u8 {
+		if a < 0x4000 { self.rom[a] }
+		else { self.rom[self.rombank * 0x4000 | ((a as u32) & 0x3FFF)] }
+	}
+	fn readram(&self, a: u16) -> u8 {
+		if !self.ram_on { return 0 }
+		self.ram[self.rambank_idx() * 0x2000 | ((a as u32) & 0x1FFF)]
+	}
+	fn writerom(&mut self, a: u16, v: u8) {
+		match a {
+			0x0000 .. 0x1FFF => self.ram_on = (v == 0x0A),
+			0x2000 .. 0x2FFF => self.rombank = (self.rombank & 0x100) | (v as u32),
+			0x3000 .. 0x3FFF => self.rombank = (self.rombank & 0xFF) | (((v & 0x01) as u32) << 8),
+			0x4000 .. 0x5FFF => self.rambank = (v as u32) & 0x0F,
+			0x6000 .. 0x7FFF => {},
+			_ => fail!("Could not write to {:04X} (MBC5)", a),
+		}
+	}
+	fn writeram(&mut self, a: u16, v: u8) {
+		if !self.ram_on { return }
+		let rambank = self.rambank_idx();
+		self.ram[rambank * 0x2000 | ((a as u32) & 0x1FFF)] = v;
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => ::std::io::File::create(&path).write(self.ram),
+		};
+		self.dirty.set(false);
+	}
+}
+
+impl MBC for HuC1 {
+	fn readrom(&self, a: u16) -> u8 {
+		if a < 0x4000 { self.rom[a] }
+		else { self.rom[self.rombank * 0x4000 | ((a as u32) & 0x3FFF)] }
+	}
+	fn readram(&self, a: u16) -> u8 {
+		if self.ir_mode { return 0xC0 } // no IR light received
+		if !self.ram_on { return 0 }
+		let rambank = if self.ram_mode { self.rambank } else { 0 };
+		self.ram[rambank * 0x2000 | ((a as u32) & 0x1FFF)]
+	}
+	fn writerom(&mut self, a: u16, v: u8) {
+		match a {
+			0x0000 .. 0x1FFF => match v & 0x0F {
+				0x0E => { self.ram_on = false; self.ir_mode = true; },
+				_ => { self.ram_on = true; self.ir_mode = false; },
+			},
+			0x2000 .. 0x3FFF => {
+				self.rombank = (self.rombank & 0x60) | match (v as u32) & 0x1F { 0 => 1, n => n }
+			},
+			0x4000 .. 0x5FFF => {
+				if !self.ram_mode {
+					self.rombank = self.rombank & 0x1F | (((v as u32) & 0x03) << 5)
+				} else {
+					self.rambank = (v as u32) & 0x03;
+				}
+			},
+			0x6000 .. 0x7FFF => { self.ram_mode = (v & 0x01) == 0x01; },
+			_ => fail!("Could not write to {:04X} (HuC1)", a),
+		}
+	}
+	fn writeram(&mut self, a: u16, v: u8) {
+		if self.ir_mode { return } // no IR emitter implemented
+		if !self.ram_on { return }
+		let rambank = if self.ram_mode { self.rambank } else { 0 };
+		self.ram[rambank * 0x2000 | ((a as u32) & 0x1FFF)] = v;
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => ::std::io::File::create(&path).write(self.ram),
+		};
+		self.dirty.set(false);
+	}
+}
+
+impl MBC for HuC3 {
+	fn readrom(&self, a: u16) -> u8 {
+		if a < 0x4000 { self.rom[a] }
+		else { self.rom[self.rombank * 0x4000 | ((a as u32) & 0x3FFF)] }
+	}
+	fn readram(&self, a: u16) -> u8 {
+		if self.rambank <= 0x03 {
+			if !self.ram_on { return 0 }
+			self.ram[self.rambank * 0x2000 | ((a as u32) & 0x1FFF)]
+		} else {
+			self.hc_register()
+		}
+	}
+	fn writerom(&mut self, a: u16, v: u8) {
+		match a {
+			0x0000 .. 0x1FFF => self.ram_on = (v & 0x0F) == 0x0A,
+			0x2000 .. 0x3FFF => {
+				self.rombank = match (v as u32) & 0x7F { 0 => 1, n => n }
+			},
+			0x4000 .. 0x5FFF => self.rambank = v as u32,
+			0x6000 .. 0x7FFF => {},
+			_ => fail!("Could not write to {:04X} (HuC3)", a),
+		}
+	}
+	fn writeram(&mut self, a: u16, v: u8) {
+		if self.rambank <= 0x03 {
+			if !self.ram_on { return }
+			self.ram[self.rambank * 0x2000 | ((a as u32) & 0x1FFF)] = v;
+		} else {
+			self.hc_command = v;
+		}
+		self.dirty.set(true);
+	}
+
+	fn save(&self) {
+		if !self.dirty.get() { return }
+		match self.savepath.clone() {
+			None => {},
+			Some(path) => ::std::io::File::create(&path).write(self.ram),
+		};
+		self.dirty.set(false);
 	}
 }